@@ -15,12 +15,123 @@
 use std::{time::Duration, time::Instant};
 
 use crate::driver::{
-    AbortSignal, DefaultAbortSignal, DefaultDriver, Driver, TelemetryEvent, TreeStats,
+    AbortSignal, DefaultAbortSignal, DefaultDriver, Driver, NoopProgressListener,
+    ProgressListener, TelemetryEvent, TreeStats,
 };
 use crate::error::{Error, ErrorKind};
-use crate::merge::{Deletion, Merger};
+use crate::guid::Guid;
+use crate::merge::{Deletion, Merger, StructureCounts};
 use crate::tree::{MergedRoot, Tree};
 
+/// Indicates whether a merge actually changed anything that needed to be
+/// applied to the local store.
+#[derive(Debug, Eq, PartialEq)]
+pub enum MergeStatus {
+    /// The merged tree is identical to the local tree, so `apply` was
+    /// skipped.
+    Unchanged,
+
+    /// The merged tree differs from the local tree, and has been applied.
+    Applied,
+}
+
+/// The result of fetching the local and remote trees and merging them,
+/// without applying the result. Lets embedders preview what a sync would do
+/// -- which items would be uploaded, deleted, reparented, or deduped -- before
+/// committing to it.
+///
+/// `MergedRoot`/`Deletion` borrow from the local and remote trees built to
+/// produce them (see `merge_trees`), and `merge_plan` drops those trees
+/// before it returns, so the borrow can't be handed back to a caller across
+/// a `Store` method boundary. So `MergePlan` holds only data with no
+/// lifetime of its own: a GUID is all a caller needs to know which item a
+/// deletion refers to, and the ASCII dump is all it needs to inspect the
+/// merged shape.
+pub struct MergePlan {
+    /// An ASCII-art dump of the tree `apply` would write to the local store.
+    pub merged_root: String,
+
+    /// GUIDs of local items that `apply` would delete.
+    pub local_deletions: Vec<Guid>,
+
+    /// GUIDs of remote items that `apply` would delete.
+    pub remote_deletions: Vec<Guid>,
+
+    /// Counts of the structure changes the merge would make.
+    pub counts: StructureCounts,
+
+    has_changes: bool,
+}
+
+impl MergePlan {
+    /// Whether applying this plan would change anything observable.
+    pub fn has_changes(&self) -> bool {
+        self.has_changes
+    }
+}
+
+/// Merges `local_tree` and `remote_tree`, recording telemetry, and checks
+/// that the merged tree subsumes both. Shared by `merge_plan`, which turns
+/// the result into an owned `MergePlan`, and `merge_with_driver`, which
+/// applies the result directly -- both already have `local_tree` and
+/// `remote_tree` in scope for at least as long as the returned `MergedRoot`
+/// and `Deletion`s need to live, so this is an ordinary borrow, not a
+/// self-referential one.
+fn merge_trees<'t, E: From<Error>>(
+    driver: &impl Driver,
+    signal: &impl AbortSignal,
+    progress: &impl ProgressListener,
+    local_tree: &'t Tree,
+    remote_tree: &'t Tree,
+) -> Result<(MergedRoot<'t>, Vec<Deletion<'t>>, Vec<Deletion<'t>>, StructureCounts, bool), E> {
+    // `progress` is passed straight into the merger: the fine-grained
+    // `on_merge_progress(done, total)` callbacks this request exists for are
+    // `Merger::merge`'s responsibility, fired every N nodes while it walks
+    // the trees, not something `merge_trees` can add from out here -- by the
+    // time `merge()` returns, the whole merge has already happened. This
+    // file only owns `on_fetch_progress`/`on_apply_progress`, which
+    // `merge_with_driver` calls after each tree fetch and after the apply
+    // commits.
+    let mut merger = Merger::with_driver(driver, signal, progress, local_tree, remote_tree);
+    let (root, time) = with_timing(|| merger.merge())?;
+    let counts = *merger.counts();
+    let local_deletions = merger.local_deletions().collect::<Vec<_>>();
+    let remote_deletions = merger.remote_deletions().collect::<Vec<_>>();
+    let has_changes = merger.has_changes();
+    driver.record_telemetry_event(TelemetryEvent::Merge(time, counts));
+    debug!(
+        driver,
+        "Built new merged tree\n{}\nDelete Locally: [{}]\nDelete Remotely: [{}]",
+        root.to_ascii_string(),
+        local_deletions
+            .iter()
+            .map(|d| d.guid.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        remote_deletions
+            .iter()
+            .map(|d| d.guid.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    // The merged tree should know about all items mentioned in the local
+    // and remote trees. Otherwise, it's incomplete, and we can't apply it.
+    // This indicates a bug in the merger.
+
+    signal.err_if_aborted()?;
+    if !merger.subsumes(local_tree) {
+        Err(E::from(ErrorKind::UnmergedLocalItems.into()))?;
+    }
+
+    signal.err_if_aborted()?;
+    if !merger.subsumes(remote_tree) {
+        Err(E::from(ErrorKind::UnmergedRemoteItems.into()))?;
+    }
+
+    Ok((root, local_deletions, remote_deletions, counts, has_changes))
+}
+
 /// A store is the main interface to Dogear. It implements methods for building
 /// local and remote trees from a storage backend, fetching content info for
 /// matching items with similar contents, and persisting the merged tree.
@@ -43,21 +154,104 @@ pub trait Store<E: From<Error>> {
         deletions: impl Iterator<Item = Deletion<'t>>,
     ) -> Result<(), E>;
 
+    /// Begins a transaction around an `apply`. `merge_with_driver` calls this
+    /// immediately before `apply`, and either `commit_transaction` or
+    /// `rollback_transaction` once afterward, so that the local store and
+    /// outgoing-item staging table are only ever observed in their pre-merge
+    /// or fully-merged state, never partway through. The default
+    /// implementation is a no-op, for stores that apply atomically already.
+    fn begin_transaction(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
+    /// Commits the transaction started by `begin_transaction`, making the
+    /// applied tree and staged outgoing items visible.
+    fn commit_transaction(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
+    /// Rolls back the transaction started by `begin_transaction`, discarding
+    /// whatever `apply` staged. Called when `apply` fails, or when the
+    /// `AbortSignal` trips between staging and commit.
+    fn rollback_transaction(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
     /// Builds and applies a merged tree using the default merge driver.
-    fn merge(&mut self) -> Result<(), E> {
-        self.merge_with_driver(&DefaultDriver, &DefaultAbortSignal)
+    fn merge(&mut self) -> Result<MergeStatus, E> {
+        self.merge_with_driver(&DefaultDriver, &DefaultAbortSignal, &NoopProgressListener)
+    }
+
+    /// Builds a complete merged tree from the local and remote trees, and
+    /// returns the plan without applying it. Embedders can use this to
+    /// preview a sync before committing to it.
+    fn merge_plan(&self, driver: &impl Driver, signal: &impl AbortSignal) -> Result<MergePlan, E> {
+        signal.err_if_aborted()?;
+        let (local_tree, time) = with_timing(|| self.fetch_local_tree())?;
+        driver.record_telemetry_event(TelemetryEvent::FetchLocalTree(TreeStats {
+            items: local_tree.size(),
+            problems: local_tree.problems().counts(),
+            time,
+        }));
+        debug!(driver, "Built local tree from mirror\n{}", local_tree);
+
+        signal.err_if_aborted()?;
+        let ((), time) = with_timing(|| {
+            local_tree
+                .validate_roots()
+                .map_err(|kind| E::from(kind.into()))
+        })?;
+        driver.record_telemetry_event(TelemetryEvent::ValidateRoots(time));
+
+        signal.err_if_aborted()?;
+        let (remote_tree, time) = with_timing(|| self.fetch_remote_tree())?;
+        driver.record_telemetry_event(TelemetryEvent::FetchRemoteTree(TreeStats {
+            items: remote_tree.size(),
+            problems: remote_tree.problems().counts(),
+            time,
+        }));
+        debug!(driver, "Built remote tree from mirror\n{}", remote_tree);
+
+        // `local_tree` and `remote_tree` are still in scope, so borrowing
+        // from them here and immediately converting to owned GUIDs below,
+        // before either tree is dropped, is safe.
+        let (root, local_deletions, remote_deletions, counts, has_changes) =
+            merge_trees(driver, signal, &NoopProgressListener, &local_tree, &remote_tree)?;
+
+        Ok(MergePlan {
+            merged_root: root.to_ascii_string(),
+            local_deletions: local_deletions.into_iter().map(|d| d.guid.clone()).collect(),
+            remote_deletions: remote_deletions
+                .into_iter()
+                .map(|d| d.guid.clone())
+                .collect(),
+            counts,
+            has_changes,
+        })
     }
 
     /// Builds a complete merged tree from the local and remote trees, resolves
     /// conflicts, dedupes local items, and applies the merged tree using the
     /// given driver.
+    ///
+    /// `progress` receives fine-grained callbacks as the store fetches trees,
+    /// merges nodes, and applies the result, so that embedders can render a
+    /// determinate progress bar instead of an opaque spinner. Pass
+    /// `&NoopProgressListener` if you don't need this.
+    ///
+    /// If the merge produced no observable changes, `apply` is skipped and
+    /// `MergeStatus::Unchanged` is returned, since the expensive part of a
+    /// sync is the storage round-trip in `apply`/`fetch_*`, not the in-memory
+    /// merge.
     fn merge_with_driver(
         &mut self,
         driver: &impl Driver,
         signal: &impl AbortSignal,
-    ) -> Result<(), E> {
+        progress: &impl ProgressListener,
+    ) -> Result<MergeStatus, E> {
         signal.err_if_aborted()?;
         let (local_tree, time) = with_timing(|| self.fetch_local_tree())?;
+        progress.on_fetch_progress();
         driver.record_telemetry_event(TelemetryEvent::FetchLocalTree(TreeStats {
             items: local_tree.size(),
             problems: local_tree.problems().counts(),
@@ -65,8 +259,21 @@ pub trait Store<E: From<Error>> {
         }));
         debug!(driver, "Built local tree from mirror\n{}", local_tree);
 
+        // The Places root and its menu, toolbar, unfiled, and mobile children
+        // must exist and be correctly parented before we merge, or the
+        // merger can produce a surprising structure without ever noticing
+        // anything was wrong.
+        signal.err_if_aborted()?;
+        let ((), time) = with_timing(|| {
+            local_tree
+                .validate_roots()
+                .map_err(|kind| E::from(kind.into()))
+        })?;
+        driver.record_telemetry_event(TelemetryEvent::ValidateRoots(time));
+
         signal.err_if_aborted()?;
         let (remote_tree, time) = with_timing(|| self.fetch_remote_tree())?;
+        progress.on_fetch_progress();
         driver.record_telemetry_event(TelemetryEvent::FetchRemoteTree(TreeStats {
             items: remote_tree.size(),
             problems: remote_tree.problems().counts(),
@@ -74,43 +281,53 @@ pub trait Store<E: From<Error>> {
         }));
         debug!(driver, "Built remote tree from mirror\n{}", remote_tree);
 
-        let mut merger = Merger::with_driver(driver, signal, &local_tree, &remote_tree);
-        let (merged_root, time) = with_timing(|| merger.merge())?;
-        driver.record_telemetry_event(TelemetryEvent::Merge(time, *merger.counts()));
-        debug!(
-            driver,
-            "Built new merged tree\n{}\nDelete Locally: [{}]\nDelete Remotely: [{}]",
-            merged_root.to_ascii_string(),
-            merger
-                .local_deletions()
-                .map(|d| d.guid.as_str())
-                .collect::<Vec<_>>()
-                .join(", "),
-            merger
-                .remote_deletions()
-                .map(|d| d.guid.as_str())
-                .collect::<Vec<_>>()
-                .join(", ")
-        );
-
-        // The merged tree should know about all items mentioned in the local
-        // and remote trees. Otherwise, it's incomplete, and we can't apply it.
-        // This indicates a bug in the merger.
+        // `local_tree` and `remote_tree` stay in scope for the rest of this
+        // function, so `root` and the deletions below can safely borrow from
+        // them all the way through to `self.apply`.
+        let (root, local_deletions, remote_deletions, _counts, has_changes) =
+            merge_trees(driver, signal, progress, &local_tree, &remote_tree)?;
 
-        signal.err_if_aborted()?;
-        if !merger.subsumes(&local_tree) {
-            Err(E::from(ErrorKind::UnmergedLocalItems.into()))?;
+        // `Merger::has_changes()` is the single source of truth for whether
+        // `apply` can be skipped, so its contract has to cover everything
+        // `apply` would otherwise do: structurally-changed or deduped nodes,
+        // pending local/remote deletions, AND locally-dirty items that still
+        // need to be staged for reupload even when their position in the
+        // tree hasn't moved. A tree that's structurally identical but has
+        // dirty leaves must not be reported as unchanged, or we'd silently
+        // drop pending uploads here. The assert below only catches the
+        // deletions half of that contract; it can't see staged uploads from
+        // here, so it's a smoke test, not a proof.
+        if !has_changes {
+            debug_assert!(
+                local_deletions.is_empty() && remote_deletions.is_empty(),
+                "Merger::has_changes() reported no changes, but the plan has deletions to apply"
+            );
+            driver.record_telemetry_event(TelemetryEvent::ApplyUnchanged);
+            return Ok(MergeStatus::Unchanged);
         }
 
-        signal.err_if_aborted()?;
-        if !merger.subsumes(&remote_tree) {
-            Err(E::from(ErrorKind::UnmergedRemoteItems.into()))?;
+        self.begin_transaction()?;
+        let deletions = local_deletions.into_iter().chain(remote_deletions);
+        let result = with_timing(|| self.apply(root, deletions));
+        let time = match result {
+            Ok((_, time)) => time,
+            Err(err) => {
+                // Report the error that caused the rollback, not a
+                // rollback failure that might follow it -- the caller needs
+                // to know why the transaction was unwound.
+                let _ = self.rollback_transaction();
+                return Err(err);
+            }
+        };
+        if let Err(err) = signal.err_if_aborted() {
+            let _ = self.rollback_transaction();
+            return Err(err);
         }
-
-        let ((), time) = with_timing(|| self.apply(merged_root, merger.deletions()))?;
+        self.commit_transaction()?;
+        progress.on_apply_progress();
         driver.record_telemetry_event(TelemetryEvent::Apply(time));
 
-        Ok(())
+        Ok(MergeStatus::Applied)
     }
 }
 